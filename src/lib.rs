@@ -1,11 +1,44 @@
 #![doc = include_str!("../README.md")]
 #![no_std]
 
+mod tdm;
+
+pub use tdm::TdmGroup;
+
 use embedded_hal_async::spi::SpiDevice;
 
+/// Attenuation register value for 0 dB (full scale).
+const ATTENUATION_MAX: i32 = 255;
+
+/// Lowest attenuation register value before the output is considered muted.
+const ATTENUATION_MIN: i32 = 15;
+
+/// Rounds a value to the nearest integer, away from zero on ties.
+///
+/// This is a manual replacement for `f32::round`, which is unavailable in
+/// `no_std` without pulling in `libm`.
+fn round_half_away_from_zero(value: f32) -> f32 {
+    if value >= 0.0 {
+        (value + 0.5) as i32 as f32
+    } else {
+        (value - 0.5) as i32 as f32
+    }
+}
+
+/// Highest sample rate, in Hz, supported in single-rate sampling mode.
+const SINGLE_RATE_MAX_HZ: u32 = 54_000;
+
+/// Highest sample rate, in Hz, supported in dual-rate sampling mode.
+const DUAL_RATE_MAX_HZ: u32 = 108_000;
+
+/// Highest sample rate, in Hz, supported in quad-rate sampling mode.
+const QUAD_RATE_MAX_HZ: u32 = 216_000;
+
 /// Driver for the DAC.
 pub struct Pcm4104<SPI> {
     spi: SPI,
+    verify: bool,
+    sample_rate: Option<u32>,
 }
 
 impl<SPI> Pcm4104<SPI>
@@ -14,7 +47,25 @@ where
 {
     /// Returns a new driver.
     pub fn new(spi: SPI) -> Self {
-        Self { spi }
+        Self {
+            spi,
+            verify: false,
+            sample_rate: None,
+        }
+    }
+
+    /// Enables or disables read-back verification of register writes.
+    ///
+    /// When enabled, the methods that change settings (`configure`,
+    /// `set_attenuation`, `set_attenuations`, `set_attenuations_db`,
+    /// `set_mute`, `set_power_down`, `set_sample_rate` and
+    /// `enable_de_emphasis`) reads the written register back and returns
+    /// `Error::Check` if it doesn't match, which can catch wiring or SPI
+    /// issues that would otherwise corrupt the device state silently.
+    /// `reset` and `write_register` are never verified.
+    pub fn with_verification(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
     }
 
     /// Configures the DAC with specific settings.
@@ -34,20 +85,102 @@ where
         if config.soft_mute {
             value |= 0b1111 << 4;
         }
-        self.write_register(5, value).await?;
+        self.write_register_checked(5, value).await?;
 
         // Register 6: System Control Register.
         let mut value = config.sampling_mode as u8;
         if config.power_down {
             value |= 0b11 << 2;
         }
-        self.write_register(6, value).await?;
+        self.write_register_checked(6, value).await?;
 
         // Register 7: Audio Serial Port Control Register.
         let value = config.audio_data_format as u8
             | ((config.lrck_polarity as u8) << 4)
             | ((config.bck_sampling_edge as u8) << 5);
-        self.write_register(7, value).await
+        self.write_register_checked(7, value).await
+    }
+
+    /// Reads back the current configuration from the device registers.
+    pub async fn read_config(&mut self) -> Result<Pcm4104Config, Error> {
+        let reg5 = self.read_register(5).await?;
+        let reg6 = self.read_register(6).await?;
+        let reg7 = self.read_register(7).await?;
+
+        Ok(Pcm4104Config {
+            sampling_mode: SamplingMode::try_from(reg6 & 0b11)?,
+            audio_data_format: AudioDataFormat::try_from(reg7 & 0b111)?,
+            lrck_polarity: LrckPolarity::try_from((reg7 >> 4) & 0b1)?,
+            bck_sampling_edge: BckSamplingEdge::try_from((reg7 >> 5) & 0b1)?,
+            de_emphasis: DeEmphasis::try_from(reg5 & 0b11)?,
+            output_phase: OutputPhase::try_from((reg5 >> 2) & 0b1)?,
+            zero_data_mute: reg5 & (0b1 << 3) != 0,
+            soft_mute: reg5 & (0b1111 << 4) != 0,
+            power_down: reg6 & (0b11 << 2) != 0,
+        })
+    }
+
+    /// Configures the device for a given sample rate, selecting the
+    /// matching `SamplingMode` automatically.
+    ///
+    /// The rate is remembered so that a later `enable_de_emphasis(true)`
+    /// call can validate against it. Moving off `SingleRate` also disables
+    /// de-emphasis, since the device rejects that combination.
+    pub async fn set_sample_rate(&mut self, fs_hz: u32) -> Result<(), Error> {
+        let sampling_mode = if fs_hz <= SINGLE_RATE_MAX_HZ {
+            SamplingMode::SingleRate
+        } else if fs_hz <= DUAL_RATE_MAX_HZ {
+            SamplingMode::DualRate
+        } else if fs_hz <= QUAD_RATE_MAX_HZ {
+            SamplingMode::QuadRate
+        } else {
+            return Err(Error::InvalidSampleRate);
+        };
+
+        if sampling_mode != SamplingMode::SingleRate {
+            let mut value = self.read_register(5).await?;
+            value &= !0b11;
+            self.write_register_checked(5, value).await?;
+        }
+
+        let mut value = self.read_register(6).await?;
+        value = (value & !0b11) | sampling_mode as u8;
+        self.write_register_checked(6, value).await?;
+
+        self.sample_rate = Some(fs_hz);
+        Ok(())
+    }
+
+    /// Enables or disables digital de-emphasis, auto-selecting the
+    /// `DeEmphasis` variant matching the sample rate set via
+    /// `set_sample_rate`.
+    ///
+    /// Returns `Error::DeEmphasisNotAvailable` if no sample rate has been
+    /// set yet, the sample rate has no matching de-emphasis curve, or the
+    /// device isn't currently in single-rate sampling mode.
+    pub async fn enable_de_emphasis(&mut self, enable: bool) -> Result<(), Error> {
+        let mut value = self.read_register(5).await?;
+
+        let de_emphasis = if enable {
+            let fs_hz = self.sample_rate.ok_or(Error::DeEmphasisNotAvailable)?;
+
+            let reg6 = self.read_register(6).await?;
+            if SamplingMode::try_from(reg6 & 0b11)? != SamplingMode::SingleRate {
+                return Err(Error::DeEmphasisNotAvailable);
+            }
+
+            match fs_hz {
+                32_000 => DeEmphasis::Fs32Khz,
+                44_100 => DeEmphasis::Fs44_1Khz,
+                48_000 => DeEmphasis::Fs48Khz,
+                _ => return Err(Error::DeEmphasisNotAvailable),
+            }
+        } else {
+            DeEmphasis::Disabled
+        };
+
+        value = (value & !0b11) | de_emphasis as u8;
+        self.write_register_checked(5, value).await
     }
 
     /// Sets the digital output attenuation for a channel.
@@ -56,13 +189,74 @@ where
         channel: OutputChannel,
         atten: u8,
     ) -> Result<(), Error> {
-        let addr = match channel {
+        let addr = Self::attenuation_register(channel);
+        self.write_register_checked(addr, atten).await
+    }
+
+    /// Sets the digital output attenuation for a channel in decibels.
+    ///
+    /// The PCM4104 attenuation register uses 0.5 dB steps with `0xFF`
+    /// representing 0 dB (full scale). Values above `0.0` dB are clamped to
+    /// `0.0` dB, and values below -120 dB are clamped to -120 dB (register
+    /// value 15), the lowest attenuation above mute; this method never
+    /// selects mute.
+    pub async fn set_attenuation_db(
+        &mut self,
+        channel: OutputChannel,
+        db: f32,
+    ) -> Result<(), Error> {
+        self.set_attenuation(channel, Self::db_to_attenuation(db))
+            .await
+    }
+
+    /// Reads back the digital output attenuation for a channel in decibels.
+    pub async fn attenuation_db(&mut self, channel: OutputChannel) -> Result<f32, Error> {
+        let addr = Self::attenuation_register(channel);
+        let atten = self.read_register(addr).await?;
+        Ok(Self::attenuation_to_db(atten))
+    }
+
+    /// Sets the digital output attenuation for all four channels at once,
+    /// in channel order.
+    pub async fn set_attenuations(&mut self, atten: [u8; 4]) -> Result<(), Error> {
+        for (index, value) in atten.into_iter().enumerate() {
+            self.write_register_checked(1 + index as u8, value).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the digital output attenuation for all four channels at once,
+    /// in decibels and channel order.
+    ///
+    /// The conversion from decibels to register values is performed for all
+    /// channels before any register is written.
+    pub async fn set_attenuations_db(&mut self, atten: [f32; 4]) -> Result<(), Error> {
+        let atten = atten.map(Self::db_to_attenuation);
+        self.set_attenuations(atten).await
+    }
+
+    /// Returns the register address for a channel's attenuation register.
+    fn attenuation_register(channel: OutputChannel) -> u8 {
+        match channel {
             OutputChannel::Channel1 => 1,
             OutputChannel::Channel2 => 2,
             OutputChannel::Channel3 => 3,
             OutputChannel::Channel4 => 4,
-        };
-        self.write_register(addr, atten).await
+        }
+    }
+
+    /// Converts a desired attenuation in decibels to an attenuation register
+    /// value, clamping out-of-range input instead of wrapping.
+    fn db_to_attenuation(db: f32) -> u8 {
+        let db = if db > 0.0 { 0.0 } else { db };
+        let steps = round_half_away_from_zero(2.0 * db) as i32;
+        let code = ATTENUATION_MAX + steps;
+        code.clamp(ATTENUATION_MIN, ATTENUATION_MAX) as u8
+    }
+
+    /// Converts an attenuation register value back to decibels.
+    fn attenuation_to_db(atten: u8) -> f32 {
+        (atten as i32 - ATTENUATION_MAX) as f32 / 2.0
     }
 
     /// Sets the soft mute on/off for a channel.
@@ -82,7 +276,7 @@ where
             value &= !bit_mask;
         }
 
-        self.write_register(5, value).await
+        self.write_register_checked(5, value).await
     }
 
     /// Sets the power down state for pair of channels.
@@ -106,7 +300,7 @@ where
             value &= !bit_mask;
         }
 
-        self.write_register(6, value).await
+        self.write_register_checked(6, value).await
     }
 
     /// Performs a software reset.
@@ -140,6 +334,43 @@ where
 
         self.spi.write(&tx_buf).await.map_err(|_| Error::SpiError)
     }
+
+    /// Writes a single register and reads it back to confirm the write took
+    /// effect, returning `Error::Check` if the writable bits don't match.
+    /// This check is made whether or not verification is enabled.
+    pub async fn write_register_verified(&mut self, addr: u8, value: u8) -> Result<(), Error> {
+        self.write_register(addr, value).await?;
+
+        let readback = self.read_register(addr).await?;
+        let mask = Self::writable_bits(addr);
+        if readback & mask != value & mask {
+            return Err(Error::Check);
+        }
+
+        Ok(())
+    }
+
+    /// Writes a single register, routing through `write_register_verified`
+    /// when verification is enabled.
+    async fn write_register_checked(&mut self, addr: u8, value: u8) -> Result<(), Error> {
+        if self.verify {
+            self.write_register_verified(addr, value).await
+        } else {
+            self.write_register(addr, value).await
+        }
+    }
+
+    /// Returns the mask of bits that are writable (as opposed to reserved or
+    /// read-only) for a given register, used to ignore those bits when
+    /// verifying a write.
+    fn writable_bits(addr: u8) -> u8 {
+        match addr {
+            1..=5 => 0xff,
+            6 => 0b0100_1111,
+            7 => 0b0011_0111,
+            _ => 0x00,
+        }
+    }
 }
 
 /// Driver configuration settings.
@@ -188,6 +419,19 @@ pub enum SamplingMode {
     QuadRate = 0b10,
 }
 
+impl TryFrom<u8> for SamplingMode {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b00 => Ok(Self::SingleRate),
+            0b01 => Ok(Self::DualRate),
+            0b10 => Ok(Self::QuadRate),
+            _ => Err(Error::InvalidRegisterValue),
+        }
+    }
+}
+
 /// Audio data format selection.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[repr(u8)]
@@ -218,6 +462,24 @@ pub enum AudioDataFormat {
     RightJustified16Bit = 0b111,
 }
 
+impl TryFrom<u8> for AudioDataFormat {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b000 => Ok(Self::LeftJustified24Bit),
+            0b001 => Ok(Self::I2s24Bit),
+            0b010 => Ok(Self::TdmZeroBckDelay),
+            0b011 => Ok(Self::TdmOneBckDelay),
+            0b100 => Ok(Self::RightJustified24Bit),
+            0b101 => Ok(Self::RightJustified20Bit),
+            0b110 => Ok(Self::RightJustified18Bit),
+            0b111 => Ok(Self::RightJustified16Bit),
+            _ => Err(Error::InvalidRegisterValue),
+        }
+    }
+}
+
 /// LRCK polarity selection.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[repr(u8)]
@@ -230,6 +492,18 @@ pub enum LrckPolarity {
     Inverted = 0b1,
 }
 
+impl TryFrom<u8> for LrckPolarity {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b0 => Ok(Self::Normal),
+            0b1 => Ok(Self::Inverted),
+            _ => Err(Error::InvalidRegisterValue),
+        }
+    }
+}
+
 /// Bitclock sampling edge selection.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[repr(u8)]
@@ -242,6 +516,18 @@ pub enum BckSamplingEdge {
     Falling = 0b1,
 }
 
+impl TryFrom<u8> for BckSamplingEdge {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b0 => Ok(Self::Rising),
+            0b1 => Ok(Self::Falling),
+            _ => Err(Error::InvalidRegisterValue),
+        }
+    }
+}
+
 /// De-Emphasis selection.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[repr(u8)]
@@ -260,6 +546,20 @@ pub enum DeEmphasis {
     Fs32Khz = 0b11,
 }
 
+impl TryFrom<u8> for DeEmphasis {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b00 => Ok(Self::Disabled),
+            0b01 => Ok(Self::Fs48Khz),
+            0b10 => Ok(Self::Fs44_1Khz),
+            0b11 => Ok(Self::Fs32Khz),
+            _ => Err(Error::InvalidRegisterValue),
+        }
+    }
+}
+
 /// Output phase selection.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[repr(u8)]
@@ -272,6 +572,18 @@ pub enum OutputPhase {
     Inverted = 0b1,
 }
 
+impl TryFrom<u8> for OutputPhase {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0b0 => Ok(Self::NonInverted),
+            0b1 => Ok(Self::Inverted),
+            _ => Err(Error::InvalidRegisterValue),
+        }
+    }
+}
+
 /// Output channel selection.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OutputChannel {
@@ -299,4 +611,26 @@ pub enum Error {
 
     /// De-emphasis not available in the selected sampling mode.
     DeEmphasisNotAvailable,
+
+    /// A verified register write read back a different value than the one
+    /// written.
+    Check,
+
+    /// A register held a value that doesn't correspond to any defined
+    /// setting.
+    InvalidRegisterValue,
+
+    /// Sample rate outside the range supported by any sampling mode.
+    InvalidSampleRate,
+
+    /// The total channel count of a `TdmGroup` doesn't fit in one TDM frame.
+    TdmFrameOverflow,
+
+    /// A `TdmGroup` was configured with an audio data format that isn't one
+    /// of the TDM formats.
+    TdmFormatRequired,
+
+    /// The devices in a `TdmGroup` don't share identical sampling mode and
+    /// audio data format settings, which a shared TDM bus requires.
+    TdmSettingsMismatch,
 }
@@ -0,0 +1,107 @@
+//! Coordination for PCM4104s daisy-chained on a shared TDM bus.
+
+use embedded_hal_async::spi::SpiDevice;
+
+use crate::{AudioDataFormat, Error, OutputChannel, Pcm4104, Pcm4104Config};
+
+/// Number of output channels on a single PCM4104.
+const CHANNELS_PER_DEVICE: usize = 4;
+
+/// Maximum number of channel slots in one TDM frame.
+///
+/// The PCM4104 has no register for a per-device slot index; its register map
+/// (`1`-`4` attenuation, `5` function control, `6` system control, `7` audio
+/// serial port control) carries no TDM position field, and `TdmZeroBckDelay`
+/// / `TdmOneBckDelay` only pick the BCK delay relative to LRCK used bus-wide.
+/// Slot position on the bus is fixed by how the devices' serial audio pins
+/// are wired into the TDM frame, not by anything this driver writes. `16` is
+/// a conservative cap matching the widest common audio TDM frame (TDM16);
+/// it exists only to reject device counts that obviously can't fit on any
+/// real bus before issuing SPI traffic, not to describe actual chip capacity.
+const MAX_TDM_SLOTS: usize = 16;
+
+/// A group of PCM4104 devices sharing a single TDM serial bus, each on its
+/// own chip-select.
+///
+/// This only coordinates settings that must agree across the group
+/// (sampling mode and audio data format) and lets them be written and muted
+/// together. It does not assign or configure TDM slot positions: which
+/// channel lands in which slot is determined entirely by how the devices'
+/// serial audio pins are wired into the shared bus, something outside the
+/// SPI control interface this driver speaks.
+pub struct TdmGroup<SPI, const N: usize> {
+    devices: [Pcm4104<SPI>; N],
+}
+
+impl<SPI, const N: usize> TdmGroup<SPI, N>
+where
+    SPI: SpiDevice,
+{
+    /// Returns a new group, checking that the devices fit in one TDM frame
+    /// and already agree on sampling mode and audio data format.
+    pub async fn new(mut devices: [Pcm4104<SPI>; N]) -> Result<Self, Error> {
+        if N * CHANNELS_PER_DEVICE > MAX_TDM_SLOTS {
+            return Err(Error::TdmFrameOverflow);
+        }
+
+        let mut shared_settings = None;
+        for device in &mut devices {
+            let config = device.read_config().await?;
+            let settings = (config.sampling_mode, config.audio_data_format);
+            match shared_settings {
+                None => shared_settings = Some(settings),
+                Some(expected) if expected != settings => {
+                    return Err(Error::TdmSettingsMismatch)
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(Self { devices })
+    }
+
+    /// Configures every device in the group identically.
+    ///
+    /// `config.audio_data_format` must be one of the TDM formats, since a
+    /// shared bus requires all devices to use the same serial port framing.
+    pub async fn configure_all(&mut self, config: Pcm4104Config) -> Result<(), Error> {
+        if !matches!(
+            config.audio_data_format,
+            AudioDataFormat::TdmZeroBckDelay | AudioDataFormat::TdmOneBckDelay
+        ) {
+            return Err(Error::TdmFormatRequired);
+        }
+
+        for device in &mut self.devices {
+            device.configure(config.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the soft mute on/off for all channels of all devices.
+    pub async fn set_mute_all(&mut self, mute: bool) -> Result<(), Error> {
+        for device in &mut self.devices {
+            for channel in [
+                OutputChannel::Channel1,
+                OutputChannel::Channel2,
+                OutputChannel::Channel3,
+                OutputChannel::Channel4,
+            ] {
+                device.set_mute(channel, mute).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the digital output attenuation for all channels of all devices,
+    /// in device and channel order.
+    pub async fn set_attenuations_all(&mut self, atten: [[u8; 4]; N]) -> Result<(), Error> {
+        for (device, atten) in self.devices.iter_mut().zip(atten) {
+            device.set_attenuations(atten).await?;
+        }
+
+        Ok(())
+    }
+}